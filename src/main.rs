@@ -1,11 +1,25 @@
 use bevy::prelude::*;
-use bevy::app::AppExit;
-use rand::Rng;
-use std::time::{Instant, Duration};
+use bevy::reflect::TypeUuid;
+use bevy::tasks::IoTaskPool;
+use bevy_common_assets::json::JsonAssetPlugin;
+use bevy_ggrs::{GGRSPlugin, Rollback, RollbackIdProvider, SessionType};
+use bevy_hanabi::prelude::*;
+use bytemuck::{Pod, Zeroable};
+use ggrs::{Config, InputStatus, P2PSession, PlayerHandle, PlayerType, SessionBuilder, UdpNonBlockingSocket};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use serde::Deserialize;
+use structopt::StructOpt;
+use std::net::SocketAddr;
 
 // Game constants:
 
-const SHOT_TTL: Duration = Duration::from_secs(2);
+/// Fixed simulation rate required for GGRS to resimulate frames
+/// identically on every peer.
+const FPS: usize = 60;
+const FIXED_DT: f32 = 1.0 / FPS as f32;
+
+const SHOT_TTL_FRAMES: i32 = 2 * FPS as i32;
 
 const PLAYER_BBOX: f32 = 12.0;
 const ROCK_BBOX: f32 = 12.0;
@@ -22,26 +36,133 @@ const SHOT_ANG_VEL: f32 = 0.1;
 const PLAYER_THRUST: f32 = 100.0;
 /// Rotation in radians per second.
 const PLAYER_TURN_RATE: f32 = 3.0;
-/// Refire delay between shots.
-const PLAYER_SHOT_TIME: Duration = Duration::from_millis(500);
+/// Refire delay between shots, in fixed simulation frames.
+const PLAYER_SHOT_FRAMES: i32 = FPS as i32 / 2;
+
+// Networking:
+
+bitflags::bitflags! {
+    /// The only state a peer needs to exchange each frame: which
+    /// buttons the local player is holding. Kept as a plain bitmask
+    /// (rather than reading `Res<Input<KeyCode>>` directly in gameplay
+    /// systems) so GGRS can serialize and replay it deterministically.
+    #[derive(Default)]
+    struct InputFlags: u8 {
+        const LEFT    = 1 << 0;
+        const RIGHT   = 1 << 1;
+        const THRUST  = 1 << 2;
+        const FIRE    = 1 << 3;
+        /// Starts the game from the menu, or restarts it from the
+        /// game-over screen. Carried over GGRS input rather than read
+        /// locally so the transition fires on the same session frame on
+        /// every peer.
+        const RESTART = 1 << 4;
+    }
+}
+
+/// `Pod + Zeroable` wire form of [`InputFlags`], as required by
+/// `ggrs::Config::Input`.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Pod, Zeroable)]
+struct BoxInput {
+    bits: u8,
+}
+
+struct GGRSConfig;
+impl Config for GGRSConfig {
+    type Input = BoxInput;
+    type State = u8;
+    type Address = SocketAddr;
+}
+
+/// Command line launcher: `bastroblasto --local-port 7000 --players localhost 127.0.0.1:7001 --seed 1234`.
+///
+/// `players` lists both players in handle order; the literal `localhost`
+/// marks the local player, every other entry is a remote peer's address.
+///
+/// `seed` must be the same value on every peer: it's what keeps rock
+/// spawns in lockstep, since nothing else exchanged at session start
+/// (ports, addresses) is guaranteed to match between peers.
+#[derive(StructOpt)]
+struct Opt {
+    #[structopt(long)]
+    local_port: u16,
+    #[structopt(long)]
+    players: Vec<String>,
+    #[structopt(long)]
+    seed: u64,
+}
+
+/// The game's top-level screens, gating which systems run.
+#[derive(Clone, Eq, PartialEq, Debug, Hash)]
+enum AppState {
+    /// Waiting for `levels.json` to finish loading, so the very first level
+    /// spawned sees the authored level table instead of falling back to the
+    /// procedural one.
+    Loading,
+    Menu,
+    Playing,
+    GameOver,
+}
+
+/// How actors behave at the screen edges. Chosen from the title screen
+/// and kept fixed for the rest of the session.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum BoundaryMode {
+    /// Teleport to the opposite edge, as in the original game.
+    Wrap,
+    /// Reflect velocity off the wall and clamp the position just inside it.
+    Bounce,
+}
+
+impl Default for BoundaryMode {
+    fn default() -> Self {
+        BoundaryMode::Wrap
+    }
+}
 
 // Components:
 
+/// Marks entities belonging to the title screen, so they can be
+/// despawned wholesale on exit.
+struct MenuUi;
+
+/// Marks entities belonging to the game-over screen.
+struct GameOverUi;
+
+#[derive(Reflect, Default)]
 struct Player {
-    last_shot_time: Instant
+    /// Which GGRS player handle drives this entity.
+    handle: usize,
+    last_shot_frame: i32,
 }
 
-struct Rock;
+#[derive(Reflect, Default)]
+struct Rock {
+    /// Size class: 2 = large, 1 = medium, 0 = small. Only a tier-0 rock
+    /// is destroyed outright; higher tiers split into two smaller rocks.
+    size_tier: u8,
+}
 
+/// A one-shot particle burst (explosion, thruster puff, ...), purely
+/// cosmetic. Ticked down and despawned by `despawn_finished_emitters`
+/// once its animation has had time to play out.
+struct OneShotEmitter {
+    frames_left: i32,
+}
+
+#[derive(Reflect, Default)]
 struct Shot {
-    ttl: Duration,
+    ttl: i32,
 }
 
+#[derive(Reflect, Default)]
 struct BBox {
     velocity: Vec2,
     bbox_size: f32,
 }
 
+#[derive(Reflect, Default)]
 struct Spinner {
     ang_vel: f32
 }
@@ -49,7 +170,11 @@ struct Spinner {
 #[derive(Default)]
 struct Level {
     level: u16,
-    rock_kill_count: u16
+    rock_kill_count: u16,
+    /// How many tier-0 rocks must be destroyed to clear the level: the
+    /// active [`LevelConfig`]'s `rock_count` times the 4 small fragments
+    /// each large rock splits into.
+    target_rock_count: u16,
 }
 
 #[derive(Default)]
@@ -104,24 +229,168 @@ struct ScoreBundle {
 struct PreLoadedAssets
 {
     shot_mat: Handle<ColorMaterial>,
-    rock_mat: Handle<ColorMaterial>,
+    /// Indexed by `Rock::size_tier`: 0 = small, 1 = medium, 2 = large.
+    rock_mats: [Handle<ColorMaterial>; 3],
 
     shot_sound: Handle<AudioSource>,
     hit_sound: Handle<AudioSource>,
+
+    levels: Handle<LevelsAsset>,
+}
+
+/// Radial burst played where a rock is destroyed: ~30-60 particles,
+/// short lifetime, fading from white/orange to transparent.
+struct ExplosionEffect(Handle<EffectAsset>);
+
+/// Small puff emitted behind the player while thrusting.
+struct ThrusterEffect(Handle<EffectAsset>);
+
+/// Shared across every peer, via `Opt::seed`. Rock spawns are derived from
+/// this plus the current `FrameCount` through `seeded_rng` rather than from
+/// a long-lived RNG stream, since `StdRng` itself can't be reflected and so
+/// can't be snapshotted/restored by GGRS across a resimulation.
+#[derive(Clone, Copy, Reflect, Default)]
+struct RockSeed(u64);
+
+const RNG_SALT_NEXT_LEVEL: u64 = 1;
+const RNG_SALT_ROCK_SPLIT: u64 = 2;
+
+/// Builds a short-lived RNG for one deterministic event. The same
+/// `(seed, frame, salt)` triple always yields the same stream on every
+/// peer, so nothing about the stream itself needs to survive a rollback --
+/// only `seed` (constant) and `frame` (itself rolled back) do.
+fn seeded_rng(seed: u64, frame: i32, salt: u64) -> StdRng {
+    StdRng::seed_from_u64(seed ^ (frame as u64) ^ salt.wrapping_mul(0x9E3779B97F4A7C15))
+}
+
+/// Simulation frame counter, advanced by `control` once per Playing frame
+/// and reset to 0 on restart. Rolled back alongside gameplay state so
+/// `Player::last_shot_frame` / `Shot::ttl` stay meaningful after a
+/// resimulation.
+#[derive(Reflect, Default)]
+struct FrameCount(i32);
+
+const EFFECT_SHOT: u8 = 0;
+const EFFECT_HIT: u8 = 1;
+const EFFECT_THRUST: u8 = 2;
+
+/// A cosmetic side effect (audio cue or particle burst) requested
+/// during the deterministic simulation, tagged with the frame that
+/// requested it. The rollback-scheduled systems that want a sound or a
+/// particle burst push one of these instead of triggering it directly,
+/// since GGRS may resimulate the same frame more than once; drained by
+/// `play_queued_effects` -- outside the rollback schedule -- only once
+/// that frame is confirmed, so a resimulation never plays a sound or
+/// spawns a particle twice.
+#[derive(Clone, Copy)]
+struct QueuedEffect {
+    frame: i32,
+    kind: u8,
+    x: f32,
+    y: f32,
+    size: f32,
 }
 
-impl Level {
-    fn total_rock_count(&self) -> u16
-    {
-        self.level + 4
+#[derive(Default)]
+struct EffectQueue(Vec<QueuedEffect>);
+
+/// Set by player_rock_collision instead of transitioning `State<AppState>`
+/// directly: that system runs inside the rollback schedule, and `State` is
+/// a plain resource GGRS doesn't roll back, so flipping it there would be
+/// nondeterministic across a resimulation. apply_game_over -- outside the
+/// rollback schedule -- performs the actual transition once this is set.
+#[derive(Default)]
+struct GameOverPending(bool);
+
+/// Tracks whether the synchronized RESTART input was already held on the
+/// previous rollback-tracked frame, so `sync_restart_input` reacts to the
+/// press edge instead of firing again every frame the key stays held.
+#[derive(Reflect, Default)]
+struct RestartHeld(bool);
+
+/// Set by `sync_restart_input` instead of transitioning `State<AppState>`
+/// directly, for the same reason as `GameOverPending`: that system runs
+/// inside the rollback schedule, and `State` is a plain resource GGRS
+/// doesn't roll back. `apply_restart` -- outside the rollback schedule --
+/// performs the actual transition once this is set.
+#[derive(Default)]
+struct RestartPending(bool);
+
+/// Data-driven description of a single wave, loaded from `assets/levels.json`.
+#[derive(Clone, Deserialize)]
+struct LevelConfig {
+    rock_count: u16,
+    min_rock_velocity: f32,
+    max_rock_velocity: f32,
+    rock_bbox: f32,
+    shot_refire_ms: u64,
+    /// Fixed rock spawn positions; when absent, positions are chosen
+    /// randomly away from the player as before.
+    #[serde(default)]
+    spawn_positions: Option<Vec<(f32, f32)>>,
+}
+
+impl LevelConfig {
+    /// Procedurally scaled fallback used once the authored level list is
+    /// exhausted, matching the original `level + 4` difficulty curve.
+    fn scaled(level: u16) -> Self {
+        LevelConfig {
+            rock_count: level + 4,
+            min_rock_velocity: 0.0,
+            max_rock_velocity: MAX_ROCK_VEL,
+            rock_bbox: ROCK_BBOX,
+            shot_refire_ms: PLAYER_SHOT_FRAMES as u64 * 1000 / FPS as u64,
+            spawn_positions: None,
+        }
+    }
+
+    fn shot_refire_frames(&self) -> i32 {
+        (self.shot_refire_ms as f32 / 1000.0 * FPS as f32).round() as i32
+    }
+
+    /// Guards against malformed hand-authored JSON: an inverted or empty
+    /// velocity range would panic `rng.gen_range`, a present-but-empty
+    /// `spawn_positions` would panic the `% positions.len()` index in
+    /// `next_level`, and a `rock_count` of 0 would make `target_rock_count`
+    /// 0 too, so `next_level` would fire again on the very next frame.
+    fn sanitized(mut self) -> Self {
+        if self.rock_count == 0 {
+            self.rock_count = 1;
+        }
+        if self.max_rock_velocity <= self.min_rock_velocity {
+            self.max_rock_velocity = self.min_rock_velocity + 1.0;
+        }
+        if matches!(&self.spawn_positions, Some(positions) if positions.is_empty()) {
+            self.spawn_positions = None;
+        }
+        self
+    }
+}
+
+impl Default for LevelConfig {
+    fn default() -> Self {
+        LevelConfig::scaled(0)
     }
 }
 
+/// The authored wave list, deserialized from `assets/levels.json`.
+#[derive(Deserialize, TypeUuid)]
+#[uuid = "c1c1a9c2-4b5a-4f3e-9b7a-7e9d2a2f4c10"]
+struct LevelsAsset {
+    levels: Vec<LevelConfig>,
+}
+
+/// The [`LevelConfig`] currently in effect, cached off the asset each
+/// time a new level starts so systems like `control` don't need to
+/// resolve it through `Assets<LevelsAsset>` every frame.
+#[derive(Default)]
+struct CurrentLevelConfig(LevelConfig);
+
 // Free helper functions:
 
-fn rand_orientation() -> Quat
+fn rand_orientation(rng: &mut StdRng) -> Quat
 {
-    Quat::from_rotation_z(rand::thread_rng().gen_range(0.0_f32 .. (2.0_f32 * std::f32::consts::PI)))
+    Quat::from_rotation_z(rng.gen_range(0.0_f32 .. (2.0_f32 * std::f32::consts::PI)))
 }
 
 fn test_hit(pa: Vec2, ra: f32, pb: Vec2, rb: f32) -> bool
@@ -129,50 +398,117 @@ fn test_hit(pa: Vec2, ra: f32, pb: Vec2, rb: f32) -> bool
     pa.distance_squared(pb) < (ra + rb).powi(2)
 }
 
+/// Bounding radius for a rock of the given size tier, scaled off the
+/// level's authored large-rock size.
+fn tier_bbox_size(large_bbox: f32, size_tier: u8) -> f32 {
+    match size_tier {
+        2 => large_bbox,
+        1 => large_bbox * 0.6,
+        _ => large_bbox * 0.35,
+    }
+}
+
+/// Smaller rocks are worth more points, rewarding finishing off fragments.
+fn tier_score(size_tier: u8) -> u32 {
+    match size_tier {
+        2 => 1,
+        1 => 3,
+        _ => 8,
+    }
+}
+
+/// Spawns a one-shot particle burst at `translation`, scaled by `size`,
+/// that despawns itself after `lifetime_frames`.
+fn spawn_one_shot_effect(
+    commands: &mut Commands,
+    effect: Handle<EffectAsset>,
+    translation: Vec3,
+    size: f32,
+    lifetime_frames: i32,
+) {
+    commands.spawn_bundle(ParticleEffectBundle {
+        particle_effect: ParticleEffect::new(effect),
+        transform: Transform {
+            translation,
+            scale: Vec3::splat(size),
+            ..Default::default()
+        },
+        ..Default::default()
+    }).insert(OneShotEmitter { frames_left: lifetime_frames });
+}
+
 fn next_level(
     w: &Window,
     pre_loaded_assets: &PreLoadedAssets,
+    levels_assets: &Assets<LevelsAsset>,
     commands: &mut Commands,
+    rip: &mut RollbackIdProvider,
+    seed: u64,
+    frame: i32,
     level: &mut Level,
     level_text: &mut Text,
+    current_config: &mut CurrentLevelConfig,
     exclusion: Vec2
 ) {
-    let mut rng = rand::thread_rng();
+    let rng = &mut seeded_rng(seed, frame, RNG_SALT_NEXT_LEVEL);
 
     level.rock_kill_count = 0;
     level.level += 1;
 
     level_text.sections[0].value = format!("Level: {}", level.level);
 
-    for _ in 0..level.total_rock_count() {
-        let velocity = Vec2::from(rand_orientation().mul_vec3(
-            Vec3::new(rng.gen_range(0.0..MAX_ROCK_VEL), 0.0, 0.0)
-        ));
+    let config = levels_assets.get(&pre_loaded_assets.levels)
+        .and_then(|levels| levels.levels.get((level.level - 1) as usize))
+        .cloned()
+        .unwrap_or_else(|| LevelConfig::scaled(level.level))
+        .sanitized();
+
+    // Each spawned large rock eventually yields 4 tier-0
+    // fragments (2 medium, each splitting into 2 small), so the level
+    // is cleared once that many small rocks have been destroyed.
+    level.target_rock_count = config.rock_count * 4;
 
-        let mut pos;
-        while {
-            pos = Vec2::new(
-                rng.gen_range(0.0..w.width()),
-                rng.gen_range(0.0..w.height())
-            );
-            test_hit(pos, ROCK_BBOX, exclusion, PLAYER_BBOX*3.0)
-        } {};
+    for i in 0..config.rock_count {
+        let velocity = Vec2::from(rand_orientation(rng).mul_vec3(
+            Vec3::new(rng.gen_range(config.min_rock_velocity..config.max_rock_velocity), 0.0, 0.0)
+        ));
 
-        let translation = Vec3::from((pos, 0.0));
+        let translation = if let Some(positions) = &config.spawn_positions {
+            let (x, y) = positions[i as usize % positions.len()];
+            // Authored positions are corner-origin, y-down (matching
+            // levels.json's intuitive top-left layout); convert to the
+            // engine's centered, y-up world space. Spawning in corner
+            // coordinates directly would place rocks well outside the
+            // arena, which Bounce mode immediately clamps onto the
+            // top/right walls instead of letting them drift in.
+            Vec3::new(x - w.width() * 0.5, w.height() * 0.5 - y, 0.0)
+        } else {
+            let mut pos;
+            while {
+                pos = Vec2::new(
+                    rng.gen_range(-w.width() * 0.5..w.width() * 0.5),
+                    rng.gen_range(-w.height() * 0.5..w.height() * 0.5)
+                );
+                test_hit(pos, config.rock_bbox, exclusion, PLAYER_BBOX*3.0)
+            } {};
+            Vec3::from((pos, 0.0))
+        };
 
         (*commands).spawn_bundle(RockBundle{
-            rock: Rock,
+            rock: Rock{ size_tier: 2 },
             bbox: BBox{
                 velocity,
-                bbox_size: ROCK_BBOX
+                bbox_size: config.rock_bbox
             },
             sprite: SpriteBundle {
-                material: pre_loaded_assets.rock_mat.clone(),
+                material: pre_loaded_assets.rock_mats[2].clone(),
                 transform: Transform{translation,..Default::default()},
                 ..Default::default()
             },
-        });
+        }).insert(rip.next_id());
     }
+
+    current_config.0 = config;
 }
 
 fn write_score(score: &Score) -> String
@@ -187,15 +523,64 @@ fn setup(
     windows: Res<Windows>,
     asset_server: Res<AssetServer>,
     mut materials: ResMut<Assets<ColorMaterial>>,
-    mut pre_loaded_assets: ResMut<PreLoadedAssets>)
+    mut pre_loaded_assets: ResMut<PreLoadedAssets>,
+    mut rip: ResMut<RollbackIdProvider>,
+    mut effects: ResMut<Assets<EffectAsset>>)
 {
     // Load all assets
     pre_loaded_assets.shot_mat = materials.add(asset_server.load("shot.png").into());
-    pre_loaded_assets.rock_mat = materials.add(asset_server.load("rock.png").into());
+    pre_loaded_assets.rock_mats = [
+        materials.add(asset_server.load("rock_small.png").into()),
+        materials.add(asset_server.load("rock_medium.png").into()),
+        materials.add(asset_server.load("rock_large.png").into()),
+    ];
 
     pre_loaded_assets.shot_sound = asset_server.load("pew.ogg");
     pre_loaded_assets.hit_sound = asset_server.load("boom.ogg");
 
+    pre_loaded_assets.levels = asset_server.load("levels.json");
+
+    let mut explosion_color = Gradient::new();
+    explosion_color.add_key(0.0, Vec4::new(1.0, 1.0, 1.0, 1.0));
+    explosion_color.add_key(0.4, Vec4::new(1.0, 0.6, 0.1, 1.0));
+    explosion_color.add_key(1.0, Vec4::new(1.0, 0.3, 0.0, 0.0));
+
+    commands.insert_resource(ExplosionEffect(effects.add(
+        EffectAsset::new(64, Spawner::once(45.0.into(), true))
+            .with_name("rock_explosion")
+            .init(InitPositionSphereModifier {
+                radius: 2.0,
+                dimension: ShapeDimension::Volume,
+                ..Default::default()
+            })
+            .init(InitVelocitySphereModifier {
+                speed: Value::Uniform((40.0, 120.0)),
+                ..Default::default()
+            })
+            .init(InitLifetimeModifier { lifetime: 0.4.into() })
+            .render(ColorOverLifetimeModifier { gradient: explosion_color }),
+    )));
+
+    let mut thruster_color = Gradient::new();
+    thruster_color.add_key(0.0, Vec4::new(1.0, 0.8, 0.3, 0.8));
+    thruster_color.add_key(1.0, Vec4::new(1.0, 0.3, 0.0, 0.0));
+
+    commands.insert_resource(ThrusterEffect(effects.add(
+        EffectAsset::new(16, Spawner::once(4.0.into(), true))
+            .with_name("player_thruster")
+            .init(InitPositionSphereModifier {
+                radius: 1.0,
+                dimension: ShapeDimension::Volume,
+                ..Default::default()
+            })
+            .init(InitVelocitySphereModifier {
+                speed: Value::Uniform((5.0, 20.0)),
+                ..Default::default()
+            })
+            .init(InitLifetimeModifier { lifetime: 0.15.into() })
+            .render(ColorOverLifetimeModifier { gradient: thruster_color }),
+    )));
+
     let font = asset_server.load("LiberationMono-Regular.ttf");
 
     // Create camera
@@ -205,8 +590,12 @@ fn setup(
     let w = windows.get_primary().expect("Window must exist!");
     let to_top_left = Vec3::new(-w.width() * 0.5, w.height() * 0.5, 0.0);
 
-    let mut level: Level = Default::default();
-    let mut level_text = Text::with_section(
+    // Level 1 isn't spawned here: levels.json may still be loading, and
+    // next_level needs it resolved to use the authored table instead of the
+    // procedural fallback. wait_for_levels_asset spawns it once that load
+    // has finished.
+    let level: Level = Default::default();
+    let level_text = Text::with_section(
         "",
         TextStyle {
             font: font.clone(),
@@ -219,8 +608,6 @@ fn setup(
         },
     );
 
-    next_level(w, &pre_loaded_assets, &mut commands, &mut level, &mut level_text, Vec2::ZERO);
-
     commands.spawn_bundle(LevelBundle{
         level,
         text2d: Text2dBundle{
@@ -260,86 +647,159 @@ fn setup(
     });
 
     let player_mat = materials.add(asset_server.load("player.png").into());
-    commands.spawn_bundle(PlayerBundle{
-        player: Player{
-            last_shot_time: Instant::now() - 2*PLAYER_SHOT_TIME
-        },
-        bbox: BBox{
-            velocity: Vec2::ZERO,
-            bbox_size: PLAYER_BBOX,
-        },
-        sprite: SpriteBundle {
-            material: player_mat,
-            ..Default::default()
-        }
-    });
+    for handle in 0..2 {
+        commands.spawn_bundle(PlayerBundle{
+            player: Player{
+                handle,
+                last_shot_frame: -2 * PLAYER_SHOT_FRAMES,
+            },
+            bbox: BBox{
+                velocity: Vec2::ZERO,
+                bbox_size: PLAYER_BBOX,
+            },
+            sprite: SpriteBundle {
+                material: player_mat.clone(),
+                ..Default::default()
+            }
+        }).insert(rip.next_id());
+    }
 }
 
-fn control(mut commands: Commands,
-    keyboard_input: Res<Input<KeyCode>>,
-    time: Res<Time>, audio: Res<Audio>,
+/// Polls `levels.json`'s load status and, once it has resolved, spawns
+/// level 1 and moves on to the menu. Runs in `AppState::Loading`, the
+/// state the game starts in, so `next_level`'s first call always sees the
+/// authored level table rather than racing the asset server.
+fn wait_for_levels_asset(
+    mut commands: Commands,
+    mut state: ResMut<State<AppState>>,
+    windows: Res<Windows>,
     pre_loaded_assets: Res<PreLoadedAssets>,
-    mut query: Query<(&mut Player, &mut Transform, &mut BBox)>)
+    levels_assets: Res<Assets<LevelsAsset>>,
+    mut rip: ResMut<RollbackIdProvider>,
+    rock_seed: Res<RockSeed>,
+    frame: Res<FrameCount>,
+    mut current_config: ResMut<CurrentLevelConfig>,
+    mut level_query: Query<(&mut Level, &mut Text)>)
 {
-    let mut direction = 0.0;
+    if levels_assets.get(&pre_loaded_assets.levels).is_none() {
+        return;
+    }
+
+    let window = windows.get_primary().expect("Window must exist!");
+    let (mut level, mut level_text) = level_query.single_mut().expect("Level must exist!");
+    next_level(window, &pre_loaded_assets, &levels_assets, &mut commands, &mut rip, rock_seed.0, frame.0,
+        &mut level, &mut level_text, &mut current_config, Vec2::ZERO);
+
+    state.set(AppState::Menu).expect("state transition must succeed");
+}
+
+/// GGRS input collection system: samples local keyboard state into the
+/// wire format exchanged with the remote peer every fixed frame.
+fn input(_handle: In<PlayerHandle>, keyboard_input: Res<Input<KeyCode>>) -> BoxInput {
+    let mut flags = InputFlags::empty();
+
     if keyboard_input.pressed(KeyCode::Left) {
-        direction += 1.0;
+        flags.insert(InputFlags::LEFT);
     }
     if keyboard_input.pressed(KeyCode::Right) {
-        direction -= 1.0;
+        flags.insert(InputFlags::RIGHT);
     }
+    if keyboard_input.pressed(KeyCode::Up) {
+        flags.insert(InputFlags::THRUST);
+    }
+    if keyboard_input.pressed(KeyCode::Space) {
+        flags.insert(InputFlags::FIRE);
+    }
+    if keyboard_input.pressed(KeyCode::Return) {
+        flags.insert(InputFlags::RESTART);
+    }
+
+    BoxInput { bits: flags.bits() }
+}
 
-    let thrust = keyboard_input.pressed(KeyCode::Up);
+fn control(mut commands: Commands,
+    inputs: Res<Vec<(BoxInput, InputStatus)>>,
+    pre_loaded_assets: Res<PreLoadedAssets>,
+    current_config: Res<CurrentLevelConfig>,
+    mut rip: ResMut<RollbackIdProvider>,
+    mut frame: ResMut<FrameCount>,
+    mut effect_queue: ResMut<EffectQueue>,
+    mut query: Query<(&mut Player, &mut Transform, &mut BBox)>)
+{
+    frame.0 += 1;
 
-    let shot = keyboard_input.pressed(KeyCode::Space);
+    for (mut player, mut t, mut bx) in query.iter_mut() {
+        let flags = InputFlags::from_bits_truncate(inputs[player.handle].0.bits);
 
-    let dt = time.delta_seconds();
+        let mut direction = 0.0;
+        if flags.contains(InputFlags::LEFT) {
+            direction += 1.0;
+        }
+        if flags.contains(InputFlags::RIGHT) {
+            direction -= 1.0;
+        }
 
-    let (mut player, mut t, mut bx) = query.single_mut().expect("Player must exist!");
+        let thrust = flags.contains(InputFlags::THRUST);
+        let shot = flags.contains(InputFlags::FIRE);
 
-    // First rotate the Player:
-    t.rotate(Quat::from_rotation_z(dt * PLAYER_TURN_RATE * direction));
+        // First rotate the Player:
+        t.rotate(Quat::from_rotation_z(FIXED_DT * PLAYER_TURN_RATE * direction));
 
-    // Then accelerate player in thrust direction:
-    let forward_dir = Vec2::from(t.rotation.mul_vec3(Vec3::Y));
-    if thrust {
-        let thrust_delta = dt * PLAYER_THRUST * forward_dir;
-        bx.velocity += thrust_delta;
+        // Then accelerate player in thrust direction:
+        let forward_dir = Vec2::from(t.rotation.mul_vec3(Vec3::Y));
+        if thrust {
+            let thrust_delta = FIXED_DT * PLAYER_THRUST * forward_dir;
+            bx.velocity += thrust_delta;
+
+            // Clamp the velocity to the max efficiently
+            let norm_sq = bx.velocity.length_squared();
+            if norm_sq > MAX_PHYSICS_VEL.powi(2) {
+                bx.velocity = bx.velocity / norm_sq.sqrt() * MAX_PHYSICS_VEL;
+            }
 
-        // Clamp the velocity to the max efficiently
-        let norm_sq = bx.velocity.length_squared();
-        if norm_sq > MAX_PHYSICS_VEL.powi(2) {
-            bx.velocity = bx.velocity / norm_sq.sqrt() * MAX_PHYSICS_VEL;
+            // Thruster trail, emitted just behind the ship. Queued rather than
+            // spawned here: this system runs inside the rollback schedule, and
+            // GGRS may resimulate this frame more than once.
+            let trail_pos = t.translation - Vec3::from((forward_dir, 0.0)) * PLAYER_BBOX;
+            effect_queue.0.push(QueuedEffect {
+                frame: frame.0,
+                kind: EFFECT_THRUST,
+                x: trail_pos.x,
+                y: trail_pos.y,
+                size: 1.0,
+            });
         }
-    }
 
-    // If possible, shot
-    if shot {
-        if let Some(now) = time.last_update() {
-            if now.saturating_duration_since(player.last_shot_time) > PLAYER_SHOT_TIME {
-                player.last_shot_time = now;
-
-                let velocity = SHOT_SPEED * forward_dir + bx.velocity;
-
-                commands.spawn_bundle(ShotBundle {
-                    shot: Shot{
-                        ttl: SHOT_TTL
-                    },
-                    bbox: BBox{
-                        bbox_size: SHOT_BBOX,
-                        velocity
-                    },
-                    spinner: Spinner{
-                        ang_vel: SHOT_ANG_VEL
-                    },
-                    sprite: SpriteBundle {
-                        material: pre_loaded_assets.shot_mat.clone(),
-                        transform: *t,
-                        ..Default::default()
-                    }
-                });
-                audio.play(pre_loaded_assets.shot_sound.clone());
-            }
+        // If possible, shot
+        if shot && frame.0 - player.last_shot_frame > current_config.0.shot_refire_frames() {
+            player.last_shot_frame = frame.0;
+
+            let velocity = SHOT_SPEED * forward_dir + bx.velocity;
+
+            commands.spawn_bundle(ShotBundle {
+                shot: Shot{
+                    ttl: SHOT_TTL_FRAMES
+                },
+                bbox: BBox{
+                    bbox_size: SHOT_BBOX,
+                    velocity
+                },
+                spinner: Spinner{
+                    ang_vel: SHOT_ANG_VEL
+                },
+                sprite: SpriteBundle {
+                    material: pre_loaded_assets.shot_mat.clone(),
+                    transform: *t,
+                    ..Default::default()
+                }
+            }).insert(rip.next_id());
+            effect_queue.0.push(QueuedEffect {
+                frame: frame.0,
+                kind: EFFECT_SHOT,
+                x: t.translation.x,
+                y: t.translation.y,
+                size: 0.0,
+            });
         }
     }
 }
@@ -365,53 +825,284 @@ fn wrap_actor_position(t: &mut Transform, sx: f32, sy: f32) {
     };
 }
 
-fn update_box_position(windows: Res<Windows>, time: Res<Time>, mut query: Query<(&mut Transform, &mut BBox)>)
+/// Reflects an actor's velocity off whichever screen edge it has
+/// crossed, and clamps its position just inside that edge.
+fn bounce_actor_position(t: &mut Transform, bx: &mut BBox, sx: f32, sy: f32) {
+    let screen_x_bounds = sx / 2.0 - bx.bbox_size;
+    let screen_y_bounds = sy / 2.0 - bx.bbox_size;
+    if t.translation.x > screen_x_bounds {
+        t.translation.x = screen_x_bounds;
+        bx.velocity.x = -bx.velocity.x.abs();
+    } else if t.translation.x < -screen_x_bounds {
+        t.translation.x = -screen_x_bounds;
+        bx.velocity.x = bx.velocity.x.abs();
+    }
+    if t.translation.y > screen_y_bounds {
+        t.translation.y = screen_y_bounds;
+        bx.velocity.y = -bx.velocity.y.abs();
+    } else if t.translation.y < -screen_y_bounds {
+        t.translation.y = -screen_y_bounds;
+        bx.velocity.y = bx.velocity.y.abs();
+    }
+}
+
+fn update_box_position(
+    windows: Res<Windows>,
+    boundary_mode: Res<BoundaryMode>,
+    mut query: Query<(&mut Transform, &mut BBox, Option<&Shot>)>)
 {
     let window = windows.get_primary().expect("Window must exist!");
-    let dt = time.delta_seconds();
 
-    for (mut t, bx) in query.iter_mut() {
+    for (mut t, mut bx, shot) in query.iter_mut() {
         // Translate it:
-        let dv = dt * bx.velocity;
+        let dv = FIXED_DT * bx.velocity;
         t.translation += Vec3::from((dv, 0.0));
 
-        wrap_actor_position(&mut *t, window.width(), window.height());
+        // Shots always wrap; they're removed by TTL rather than by
+        // bouncing off walls.
+        if shot.is_some() || *boundary_mode == BoundaryMode::Wrap {
+            wrap_actor_position(&mut *t, window.width(), window.height());
+        } else {
+            bounce_actor_position(&mut *t, &mut *bx, window.width(), window.height());
+        }
     }
 }
 
-fn update_spinner_spin(time: Res<Time>, mut query: Query<(&mut Transform, &mut Spinner)>)
+fn rock_rock_collision(
+    boundary_mode: Res<BoundaryMode>,
+    mut query: Query<(&mut Transform, &mut BBox), With<Rock>>)
 {
-    let dt = time.delta_seconds();
+    if *boundary_mode != BoundaryMode::Bounce {
+        return;
+    }
 
-    for (mut t, sp) in query.iter_mut() {
-        t.rotate(Quat::from_rotation_z(dt * sp.ang_vel));
+    let mut combinations = query.iter_combinations_mut::<2>();
+    while let Some([(mut ta, mut ba), (mut tb, mut bb)]) = combinations.fetch_next() {
+        let pa = Vec2::from(ta.translation);
+        let pb = Vec2::from(tb.translation);
+
+        let delta = pb - pa;
+        if !test_hit(pa, ba.bbox_size, pb, bb.bbox_size) {
+            continue;
+        }
+
+        let normal = delta.normalize_or_zero();
+        if normal == Vec2::ZERO {
+            continue;
+        }
+
+        // Push the rocks apart along the contact normal first, so they
+        // stop overlapping regardless of how their velocities resolve.
+        let overlap = (ba.bbox_size + bb.bbox_size) - delta.length();
+        if overlap > 0.0 {
+            let correction = normal * (overlap * 0.5);
+            ta.translation -= Vec3::from((correction, 0.0));
+            tb.translation += Vec3::from((correction, 0.0));
+        }
+
+        // Equal-mass elastic collision: exchange the velocity components
+        // along the contact normal, but only while the rocks are still
+        // approaching. Without this check, two rocks that overlap for
+        // several frames while already moving apart get their normal
+        // components swapped right back every frame and end up stuck
+        // jittering together.
+        if (bb.velocity - ba.velocity).dot(normal) < 0.0 {
+            let va_n = ba.velocity.dot(normal);
+            let vb_n = bb.velocity.dot(normal);
+            ba.velocity += (vb_n - va_n) * normal;
+            bb.velocity += (va_n - vb_n) * normal;
+        }
     }
 }
 
-fn update_shot_ttl(mut commands: Commands, time: Res<Time>, mut query: Query<(Entity, &mut Shot)>)
+fn update_spinner_spin(mut query: Query<(&mut Transform, &mut Spinner)>)
 {
-    let dt = time.delta();
+    for (mut t, sp) in query.iter_mut() {
+        t.rotate(Quat::from_rotation_z(FIXED_DT * sp.ang_vel));
+    }
+}
 
+fn update_shot_ttl(mut commands: Commands, mut query: Query<(Entity, &mut Shot)>)
+{
     for (entity, mut shot) in query.iter_mut() {
-        if let Some(new_ttl) = shot.ttl.checked_sub(dt) {
-            shot.ttl = new_ttl;
-        } else {
+        shot.ttl -= 1;
+        if shot.ttl <= 0 {
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
+/// Cleans up explosion/thruster emitters once they've had time to finish
+/// playing. Purely cosmetic, so it runs outside the rollback schedule.
+fn despawn_finished_emitters(mut commands: Commands, mut query: Query<(Entity, &mut OneShotEmitter)>)
+{
+    for (entity, mut emitter) in query.iter_mut() {
+        emitter.frames_left -= 1;
+        if emitter.frames_left <= 0 {
             commands.entity(entity).despawn();
         }
     }
 }
 
+/// Plays/spawns effects queued by the rollback-scheduled systems, once GGRS
+/// has confirmed the frame that queued them -- i.e. it won't be resimulated
+/// again, so the sound or particle burst can't play twice. Runs outside the
+/// rollback schedule, like the emitters it spawns.
+fn play_queued_effects(
+    mut commands: Commands,
+    session: Res<P2PSession<GGRSConfig>>,
+    audio: Res<Audio>,
+    pre_loaded_assets: Res<PreLoadedAssets>,
+    explosion_effect: Res<ExplosionEffect>,
+    thruster_effect: Res<ThrusterEffect>,
+    mut effect_queue: ResMut<EffectQueue>,
+) {
+    let confirmed_frame = session.confirmed_frame();
+
+    effect_queue.0.retain(|effect| {
+        if effect.frame > confirmed_frame {
+            return true;
+        }
+
+        match effect.kind {
+            EFFECT_SHOT => audio.play(pre_loaded_assets.shot_sound.clone()),
+            EFFECT_HIT => {
+                audio.play(pre_loaded_assets.hit_sound.clone());
+                spawn_one_shot_effect(
+                    &mut commands,
+                    explosion_effect.0.clone(),
+                    Vec3::new(effect.x, effect.y, 0.0),
+                    effect.size,
+                    (0.5 * FPS as f32) as i32,
+                );
+            }
+            EFFECT_THRUST => spawn_one_shot_effect(
+                &mut commands,
+                thruster_effect.0.clone(),
+                Vec3::new(effect.x, effect.y, 0.0),
+                effect.size,
+                FPS as i32 / 10,
+            ),
+            _ => unreachable!("unknown queued effect kind"),
+        }
+
+        false
+    });
+}
+
+/// Performs the actual game-over state transition, outside the rollback
+/// schedule, once player_rock_collision has flagged it.
+fn apply_game_over(mut state: ResMut<State<AppState>>, mut game_over_pending: ResMut<GameOverPending>)
+{
+    if !game_over_pending.0 {
+        return;
+    }
+
+    game_over_pending.0 = false;
+    state.set(AppState::GameOver).expect("state transition must succeed");
+}
+
+/// Performs the actual Menu/GameOver -> Playing transition, outside the
+/// rollback schedule, once `sync_restart_input` has flagged it.
+fn apply_restart(mut state: ResMut<State<AppState>>, mut restart_pending: ResMut<RestartPending>)
+{
+    if !restart_pending.0 {
+        return;
+    }
+
+    restart_pending.0 = false;
+    state.set(AppState::Playing).expect("state transition must succeed");
+}
+
+/// Detects the synchronized RESTART edge and, in `AppState::Menu` or
+/// `AppState::GameOver`, flags the transition into Playing. From GameOver it
+/// first resets the session (score, level, frame count, rock/player
+/// entities); from Menu, level 1 is already spawned, so nothing is reset.
+/// Runs unconditionally -- every
+/// GGRS frame, in every `AppState` -- inside the rollback schedule, so the
+/// reset itself is resimulated identically on every peer rather than firing
+/// at a wall-clock-dependent moment local to each peer's own keypress.
+fn sync_restart_input(
+    mut commands: Commands,
+    state: Res<State<AppState>>,
+    inputs: Res<Vec<(BoxInput, InputStatus)>>,
+    mut restart_held: ResMut<RestartHeld>,
+    mut restart_pending: ResMut<RestartPending>,
+    windows: Res<Windows>,
+    pre_loaded_assets: Res<PreLoadedAssets>,
+    levels_assets: Res<Assets<LevelsAsset>>,
+    mut current_config: ResMut<CurrentLevelConfig>,
+    mut rip: ResMut<RollbackIdProvider>,
+    rock_seed: Res<RockSeed>,
+    mut frame: ResMut<FrameCount>,
+    old_actors: Query<Entity, Or<(With<Rock>, With<Shot>, With<Player>)>>,
+    player_mat_query: Query<&Handle<ColorMaterial>, With<Player>>,
+    mut text_elems: QuerySet<(
+        Query<(&mut Level, &mut Text)>,
+        Query<(&mut Score, &mut Text)>,
+    )>)
+{
+    let pressed = inputs.iter().any(|(input, _)| {
+        InputFlags::from_bits_truncate(input.bits).contains(InputFlags::RESTART)
+    });
+    let rising_edge = pressed && !restart_held.0;
+    restart_held.0 = pressed;
+
+    if !rising_edge || !matches!(state.current(), AppState::Menu | AppState::GameOver) {
+        return;
+    }
+
+    if *state.current() == AppState::GameOver {
+        let player_mat = player_mat_query.iter().next().expect("Player must exist!").clone();
+        for entity in old_actors.iter() {
+            commands.entity(entity).despawn();
+        }
+
+        let (mut score, mut score_text) = text_elems.q1_mut().single_mut().expect("Score must exist!");
+        *score = Score::default();
+        score_text.sections[0].value = write_score(&score);
+
+        let (mut level, mut level_text) = text_elems.q0_mut().single_mut().expect("Level must exist!");
+        *level = Level::default();
+        frame.0 = 0;
+
+        let window = windows.get_primary().expect("Window must exist!");
+        next_level(window, &pre_loaded_assets, &levels_assets, &mut commands, &mut rip, rock_seed.0, frame.0,
+            &mut level, &mut level_text, &mut current_config, Vec2::ZERO);
+
+        for handle in 0..2 {
+            commands.spawn_bundle(PlayerBundle{
+                player: Player{
+                    handle,
+                    last_shot_frame: -2 * PLAYER_SHOT_FRAMES,
+                },
+                bbox: BBox{
+                    velocity: Vec2::ZERO,
+                    bbox_size: PLAYER_BBOX,
+                },
+                sprite: SpriteBundle {
+                    material: player_mat.clone(),
+                    ..Default::default()
+                }
+            }).insert(rip.next_id());
+        }
+    }
+
+    restart_pending.0 = true;
+}
+
 fn player_rock_collision(
-    mut exit: EventWriter<AppExit>,
+    mut game_over_pending: ResMut<GameOverPending>,
     player_query: Query<(&Transform, &BBox), With<Player>>,
     rock_query: Query<(&Transform, &BBox), With<Rock>>)
 {
-    let (pt, pbox) = player_query.single().expect("Player must exist!");
-
-    for (rt, rbox) in rock_query.iter() {
-        if test_hit(Vec2::from(pt.translation), pbox.bbox_size,
-                    Vec2::from(rt.translation), rbox.bbox_size) {
-            exit.send(AppExit);
+    for (pt, pbox) in player_query.iter() {
+        for (rt, rbox) in rock_query.iter() {
+            if test_hit(Vec2::from(pt.translation), pbox.bbox_size,
+                        Vec2::from(rt.translation), rbox.bbox_size) {
+                game_over_pending.0 = true;
+                return;
+            }
         }
     }
 }
@@ -419,26 +1110,72 @@ fn player_rock_collision(
 fn rock_shot_collision(
     mut commands: Commands,
     windows: Res<Windows>,
-    audio: Res<Audio>,
     pre_loaded_assets: Res<PreLoadedAssets>,
+    mut rip: ResMut<RollbackIdProvider>,
+    rock_seed: Res<RockSeed>,
+    levels_assets: Res<Assets<LevelsAsset>>,
+    mut current_config: ResMut<CurrentLevelConfig>,
+    frame: Res<FrameCount>,
+    mut effect_queue: ResMut<EffectQueue>,
     mut text_elems: QuerySet<(
         Query<(&mut Level, &mut Text)>,
         Query<(&mut Score, &mut Text)>,
     )>,
     player_query: Query<&Transform, With<Player>>,
-    rock_query: Query<(Entity, &Transform, &BBox), With<Rock>>,
+    rock_query: Query<(Entity, &Transform, &BBox, &Rock)>,
     shot_query: Query<(Entity, &Transform, &BBox), With<Shot>>)
 {
+    let large_bbox = current_config.0.rock_bbox;
+
+    // Distinguishes multiple rocks destroyed on the same frame, so they
+    // don't all split with identical bounce angles.
+    let mut split_salt = 0_u64;
+
     for (re, rt, rbox) in shot_query.iter() {
-        for (se, st, sbox) in rock_query.iter() {
+        for (se, st, sbox, rock) in rock_query.iter() {
             if test_hit(Vec2::from(st.translation), sbox.bbox_size,
                         Vec2::from(rt.translation), rbox.bbox_size) {
                 commands.entity(se).despawn();
                 commands.entity(re).despawn();
-                audio.play(pre_loaded_assets.hit_sound.clone());
 
-                // Update level status
-                {
+                // Queued rather than played/spawned here: this system runs
+                // inside the rollback schedule, and GGRS may resimulate this
+                // frame more than once.
+                effect_queue.0.push(QueuedEffect {
+                    frame: frame.0,
+                    kind: EFFECT_HIT,
+                    x: st.translation.x,
+                    y: st.translation.y,
+                    size: sbox.bbox_size / ROCK_BBOX,
+                });
+
+                if rock.size_tier >= 1 {
+                    // Split into two faster, smaller fragments.
+                    let child_tier = rock.size_tier - 1;
+                    let child_bbox = tier_bbox_size(large_bbox, child_tier);
+                    for sign in [-1.0_f32, 1.0_f32] {
+                        let mut rng = seeded_rng(rock_seed.0, frame.0, RNG_SALT_ROCK_SPLIT + split_salt);
+                        split_salt += 1;
+                        let theta = sign * rng.gen_range(0.4_f32..0.8_f32);
+                        let velocity = Vec2::from(
+                            Quat::from_rotation_z(theta).mul_vec3(Vec3::from((sbox.velocity, 0.0)))
+                        ) * 1.4;
+
+                        commands.spawn_bundle(RockBundle{
+                            rock: Rock{ size_tier: child_tier },
+                            bbox: BBox{
+                                velocity,
+                                bbox_size: child_bbox,
+                            },
+                            sprite: SpriteBundle {
+                                material: pre_loaded_assets.rock_mats[child_tier as usize].clone(),
+                                transform: Transform{translation: st.translation, ..Default::default()},
+                                ..Default::default()
+                            },
+                        }).insert(rip.next_id());
+                    }
+                } else {
+                    // Tier 0 rocks are fully destroyed.
                     let (mut level, _) = text_elems.q0_mut().single_mut().expect("Level must exist!");
                     level.rock_kill_count += 1;
                 }
@@ -447,7 +1184,7 @@ fn rock_shot_collision(
                 {
                     let (mut score, mut score_text) =
                         text_elems.q1_mut().single_mut().expect("Score must exist!");
-                    score.value += 1;
+                    score.value += tier_score(rock.size_tier);
                     score_text.sections[0].value = write_score(&score);
                 }
             }
@@ -455,17 +1192,115 @@ fn rock_shot_collision(
     }
 
     let (mut level, mut level_text) = text_elems.q0_mut().single_mut().expect("Level must exist!");
-    if level.rock_kill_count == level.total_rock_count() {
+    if level.rock_kill_count == level.target_rock_count {
         // Next level:
         let window = windows.get_primary().expect("Window must exist!");
-        let player = player_query.single().expect("Player must exist!");
-        next_level(window, &pre_loaded_assets, &mut commands,
-            &mut level, &mut level_text, Vec2::from(player.translation));
+        let player = player_query.iter().next().expect("Player must exist!");
+        next_level(window, &pre_loaded_assets, &levels_assets, &mut commands, &mut rip, rock_seed.0, frame.0,
+            &mut level, &mut level_text, &mut current_config, Vec2::from(player.translation));
+    }
+}
+
+fn title_text(font: Handle<Font>, value: String) -> Text2dBundle {
+    Text2dBundle {
+        text: Text::with_section(
+            value,
+            TextStyle {
+                font,
+                font_size: 48.0,
+                color: Color::WHITE,
+            },
+            TextAlignment {
+                vertical: VerticalAlign::Center,
+                horizontal: HorizontalAlign::Center,
+            },
+        ),
+        ..Default::default()
+    }
+}
+
+fn menu_title(boundary_mode: BoundaryMode) -> String {
+    let mode_name = match boundary_mode {
+        BoundaryMode::Wrap => "Wrap",
+        BoundaryMode::Bounce => "Bounce",
+    };
+    format!(
+        "BASTROBLASTO\n\nPress Enter to start\nArena: {} (press B to change)",
+        mode_name
+    )
+}
+
+fn menu_setup(mut commands: Commands, asset_server: Res<AssetServer>, boundary_mode: Res<BoundaryMode>)
+{
+    let font = asset_server.load("LiberationMono-Regular.ttf");
+    commands.spawn_bundle(title_text(font, menu_title(*boundary_mode)))
+        .insert(MenuUi);
+}
+
+fn menu_toggle_boundary(
+    mut boundary_mode: ResMut<BoundaryMode>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mut query: Query<&mut Text, With<MenuUi>>)
+{
+    if !keyboard_input.just_pressed(KeyCode::B) {
+        return;
+    }
+
+    *boundary_mode = match *boundary_mode {
+        BoundaryMode::Wrap => BoundaryMode::Bounce,
+        BoundaryMode::Bounce => BoundaryMode::Wrap,
+    };
+
+    if let Some(mut text) = query.iter_mut().next() {
+        text.sections[0].value = menu_title(*boundary_mode);
+    }
+}
+
+fn menu_cleanup(mut commands: Commands, query: Query<Entity, With<MenuUi>>)
+{
+    for entity in query.iter() {
+        commands.entity(entity).despawn();
+    }
+}
+
+fn gameover_setup(mut commands: Commands, asset_server: Res<AssetServer>, score_query: Query<&Score>)
+{
+    let score = score_query.iter().next().expect("Score must exist!");
+    let font = asset_server.load("LiberationMono-Regular.ttf");
+    commands.spawn_bundle(title_text(
+        font,
+        format!("Game Over\n\n{}\n\nPress Enter to restart", write_score(score)),
+    )).insert(GameOverUi);
+}
+
+fn gameover_cleanup(mut commands: Commands, query: Query<Entity, With<GameOverUi>>)
+{
+    for entity in query.iter() {
+        commands.entity(entity).despawn();
     }
 }
 
 fn main()
 {
+    let opt = Opt::from_args();
+
+    let mut sess_build = SessionBuilder::<GGRSConfig>::new()
+        .with_num_players(2)
+        .with_input_delay(2)
+        .with_max_prediction_window(8);
+
+    for (i, addr) in opt.players.iter().enumerate() {
+        let player_type = if addr == "localhost" {
+            PlayerType::Local
+        } else {
+            PlayerType::Remote(addr.parse().expect("invalid player address"))
+        };
+        sess_build = sess_build.add_player(player_type, i).expect("failed to add player");
+    }
+
+    let socket = UdpNonBlockingSocket::bind_to_port(opt.local_port).expect("failed to bind socket");
+    let session = sess_build.start_p2p_session(socket).expect("failed to start GGRS session");
+
     App::build()
         .insert_resource(WindowDescriptor {
             title: "Bastroblasto!".to_string(),
@@ -474,14 +1309,123 @@ fn main()
             vsync: true,
             ..Default::default()
         })
+        .insert_resource(IoTaskPool::init())
         .insert_resource(PreLoadedAssets{..Default::default()})
+        .insert_resource(RockSeed(opt.seed))
+        .insert_resource(FrameCount::default())
+        .insert_resource(CurrentLevelConfig::default())
+        .insert_resource(BoundaryMode::default())
+        .insert_resource(EffectQueue::default())
+        .insert_resource(GameOverPending::default())
+        .insert_resource(RestartHeld::default())
+        .insert_resource(RestartPending::default())
+        .insert_resource(SessionType::P2PSession)
+        .insert_resource(session)
         .add_plugins(DefaultPlugins)
+        .add_plugin(JsonAssetPlugin::<LevelsAsset>::new(&["levels.json"]))
+        .add_plugin(HanabiPlugin)
         .add_startup_system(setup.system())
-        .add_system(control.system())
-        .add_system(update_box_position.system())
-        .add_system(update_spinner_spin.system())
-        .add_system(update_shot_ttl.system())
-        .add_system(player_rock_collision.system())
-        .add_system(rock_shot_collision.system())
+        .add_system(despawn_finished_emitters.system())
+        .add_system(play_queued_effects.system())
+        .add_system(apply_game_over.system())
+        .add_system(apply_restart.system())
+        .add_state(AppState::Loading)
+        .add_system_set(SystemSet::on_update(AppState::Loading).with_system(wait_for_levels_asset.system()))
+        .add_system_set(SystemSet::on_enter(AppState::Menu).with_system(menu_setup.system()))
+        .add_system_set(SystemSet::on_update(AppState::Menu).with_system(menu_toggle_boundary.system()))
+        .add_system_set(SystemSet::on_exit(AppState::Menu).with_system(menu_cleanup.system()))
+        .add_system_set(SystemSet::on_enter(AppState::GameOver).with_system(gameover_setup.system()))
+        .add_system_set(SystemSet::on_exit(AppState::GameOver).with_system(gameover_cleanup.system()))
+        .add_plugin(GGRSPlugin)
+        .with_input_system(input.system())
+        .register_rollback_type::<Transform>()
+        .register_rollback_type::<BBox>()
+        .register_rollback_type::<Player>()
+        .register_rollback_type::<Shot>()
+        .register_rollback_type::<Rock>()
+        .register_rollback_type::<Spinner>()
+        .register_rollback_type::<RockSeed>()
+        .register_rollback_type::<FrameCount>()
+        .register_rollback_type::<RestartHeld>()
+        .with_rollback_schedule(
+            Schedule::default()
+                .with_stage(
+                    // Runs every GGRS frame in every AppState (no run
+                    // criteria), unlike "ggrs_update" below, so the
+                    // Menu/GameOver -> Playing reset is resimulated
+                    // identically on every peer instead of depending on
+                    // wall-clock-local keyboard polling.
+                    "ggrs_state_transition",
+                    SystemStage::parallel().with_system(sync_restart_input.system()),
+                )
+                .with_stage(
+                    "ggrs_update",
+                    SystemStage::parallel()
+                        .with_run_criteria(State::on_update(AppState::Playing))
+                        .with_system(control.system())
+                        .with_system(update_box_position.system())
+                        .with_system(update_spinner_spin.system())
+                        .with_system(update_shot_ttl.system())
+                        .with_system(player_rock_collision.system())
+                        .with_system(rock_shot_collision.system())
+                        .with_system(rock_rock_collision.system()),
+                ),
+        )
         .run();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitized_clamps_zero_rock_count() {
+        let config = LevelConfig { rock_count: 0, ..LevelConfig::scaled(0) }.sanitized();
+        assert_eq!(config.rock_count, 1);
+    }
+
+    #[test]
+    fn sanitized_fixes_inverted_velocity_range() {
+        let config = LevelConfig {
+            min_rock_velocity: 10.0,
+            max_rock_velocity: 5.0,
+            ..LevelConfig::scaled(0)
+        }.sanitized();
+        assert!(config.max_rock_velocity > config.min_rock_velocity);
+    }
+
+    #[test]
+    fn sanitized_fixes_zero_width_velocity_range() {
+        let config = LevelConfig {
+            min_rock_velocity: 10.0,
+            max_rock_velocity: 10.0,
+            ..LevelConfig::scaled(0)
+        }.sanitized();
+        assert!(config.max_rock_velocity > config.min_rock_velocity);
+    }
+
+    #[test]
+    fn sanitized_normalizes_empty_spawn_positions() {
+        let config = LevelConfig {
+            spawn_positions: Some(vec![]),
+            ..LevelConfig::scaled(0)
+        }.sanitized();
+        assert!(config.spawn_positions.is_none());
+    }
+
+    #[test]
+    fn seeded_rng_is_deterministic() {
+        let mut a = seeded_rng(42, 7, RNG_SALT_NEXT_LEVEL);
+        let mut b = seeded_rng(42, 7, RNG_SALT_NEXT_LEVEL);
+        for _ in 0..8 {
+            assert_eq!(a.gen::<u64>(), b.gen::<u64>());
+        }
+    }
+
+    #[test]
+    fn seeded_rng_differs_per_salt() {
+        let mut a = seeded_rng(42, 7, RNG_SALT_NEXT_LEVEL);
+        let mut b = seeded_rng(42, 7, RNG_SALT_ROCK_SPLIT);
+        assert_ne!(a.gen::<u64>(), b.gen::<u64>());
+    }
+}